@@ -1,7 +1,15 @@
+#![feature(coerce_unsized, unsize)]
+
 use std::{
+    any::Any,
+    borrow::Borrow,
     cell::UnsafeCell,
-    mem::ManuallyDrop,
-    ops::Deref,
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    marker::Unsize,
+    mem::{ManuallyDrop, MaybeUninit},
+    ops::{CoerceUnsized, Deref},
     process::abort,
     ptr::NonNull,
     sync::atomic::{
@@ -10,21 +18,42 @@ use std::{
     },
 };
 
-struct Data<T> {
+struct Data<T: ?Sized> {
     // number of Arc
     arc_count: AtomicUsize,
     // number of Weak, plus 1 for representing all of Arcs
     alloc_count: AtomicUsize,
 
+    // must stay last so that the metadata of a fat `T` tail is preserved.
     data: UnsafeCell<ManuallyDrop<T>>,
 }
 
-pub struct Weak<T> {
+pub struct Weak<T: ?Sized> {
     ptr: NonNull<Data<T>>,
 }
 
 impl<T> Weak<T> {
+    /// Constructs a new `Weak<T>` that doesn't point at any allocation.
+    /// Calling `upgrade` on it always returns `None`.
+    pub fn new() -> Self {
+        Self {
+            ptr: NonNull::new(usize::MAX as *mut Data<T>).unwrap(),
+        }
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized> Weak<T> {
     pub fn upgrade(&self) -> Option<Arc<T>> {
+        if self.is_dangling() {
+            return None;
+        }
+
         let mut n = self.data().arc_count.load(Relaxed);
         loop {
             if n == 0 {
@@ -49,13 +78,24 @@ impl<T> Weak<T> {
     fn data(&self) -> &Data<T> {
         unsafe { self.ptr.as_ref() }
     }
+
+    // The sentinel produced by `Weak::new` stores `usize::MAX` as its
+    // (thin) data address, which no real allocation can have. Casting a
+    // fat pointer to `*const ()` keeps only that address and drops the
+    // metadata, so this works for coerced `Weak<dyn Trait>` too.
+    fn is_dangling(&self) -> bool {
+        self.ptr.as_ptr() as *const () as usize == usize::MAX
+    }
 }
 
-unsafe impl<T: Sync + Send> Send for Weak<T> {}
-unsafe impl<T: Sync + Send> Sync for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Send for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Sync for Weak<T> {}
 
-impl<T> Clone for Weak<T> {
+impl<T: ?Sized> Clone for Weak<T> {
     fn clone(&self) -> Self {
+        if self.is_dangling() {
+            return Self { ptr: self.ptr };
+        }
         if self.data().alloc_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
             abort();
         }
@@ -63,8 +103,11 @@ impl<T> Clone for Weak<T> {
     }
 }
 
-impl<T> Drop for Weak<T> {
+impl<T: ?Sized> Drop for Weak<T> {
     fn drop(&mut self) {
+        if self.is_dangling() {
+            return;
+        }
         if self.data().alloc_count.fetch_sub(1, Release) == 1 {
             fence(Acquire);
             unsafe { drop(Box::from_raw(self.ptr.as_ptr())) }
@@ -72,7 +115,9 @@ impl<T> Drop for Weak<T> {
     }
 }
 
-pub struct Arc<T> {
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<Weak<U>> for Weak<T> {}
+
+pub struct Arc<T: ?Sized> {
     ptr: NonNull<Data<T>>,
 }
 
@@ -88,9 +133,139 @@ impl<T> Arc<T> {
         }
     }
 
+    /// Constructs a new `Arc<T>` while giving `data_fn` a `Weak<T>` to
+    /// the allocation being created, so `T` can hold a back-reference to
+    /// its own `Arc` (useful for cyclic graph / observer structures that
+    /// would otherwise leak).
+    ///
+    /// Any `upgrade()` of that `Weak` performed from within `data_fn`
+    /// returns `None`, since the `Arc` doesn't exist yet.
+    pub fn new_cyclic<F>(data_fn: F) -> Arc<T>
+    where
+        F: FnOnce(&Weak<T>) -> T,
+    {
+        // Allocate with `arc_count` at 0, so a `Weak::upgrade()` from
+        // inside `data_fn` sees no live `Arc` and returns `None`, and
+        // `alloc_count` at 1 for the `Weak` we hand to the closure. That
+        // `Weak` becomes the implicit weak owned by the strong-count
+        // group once we publish the real count below, so it must not run
+        // its own `Drop`.
+        let uninit: Box<Data<MaybeUninit<T>>> = Box::new(Data {
+            arc_count: AtomicUsize::new(0),
+            alloc_count: AtomicUsize::new(1),
+            data: UnsafeCell::new(ManuallyDrop::new(MaybeUninit::uninit())),
+        });
+        // `Data<MaybeUninit<T>>` and `Data<T>` share layout, since
+        // `MaybeUninit<T>` is layout-compatible with `T`.
+        let ptr = NonNull::from(Box::leak(uninit)).cast::<Data<T>>();
+        let weak = Weak { ptr };
+
+        let data = data_fn(&weak);
+
+        unsafe {
+            ptr.as_ref().data.get().write(ManuallyDrop::new(data));
+            ptr.as_ref().arc_count.store(1, Release);
+        }
+        std::mem::forget(weak);
+
+        Arc { ptr }
+    }
+
+    /// Returns the inner value, if this is the only strong reference to
+    /// it. Otherwise, returns `this` unchanged as an `Err`.
+    ///
+    /// Any outstanding `Weak` pointers will remain valid, but will no
+    /// longer be able to `upgrade()`.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        if this
+            .data()
+            .arc_count
+            .compare_exchange(1, 0, Relaxed, Relaxed)
+            .is_err()
+        {
+            return Err(this);
+        }
+        // Acquire matches the `Release` decrement in `Drop for Arc`, so
+        // that the data is visible to us.
+        fence(Acquire);
+        // Take ownership of the implicit weak pointer so dropping it
+        // goes through the usual `alloc_count` bookkeeping, keeping any
+        // outstanding `Weak`s valid until they themselves are dropped.
+        let weak = Weak { ptr: this.ptr };
+        std::mem::forget(this);
+        let data = unsafe { ManuallyDrop::take(&mut *weak.data().data.get()) };
+        drop(weak);
+        Ok(data)
+    }
+
+    /// Returns the inner value, if this is the only strong reference to
+    /// it. Otherwise, returns `None`.
+    ///
+    /// This is the same as `try_unwrap`, except that it discards `this`
+    /// instead of handing it back when it is not unique.
+    pub fn into_inner(this: Self) -> Option<T> {
+        Arc::try_unwrap(this).ok()
+    }
+
+    /// Returns a mutable reference into the given `Arc`, cloning the
+    /// inner value into a new allocation if it is not already uniquely
+    /// owned.
+    pub fn make_mut(arc: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        // Atomically claim unique ownership of the strong count: while
+        // it reads 0, a concurrent `Weak::upgrade` sees no live `Arc`
+        // and backs off, so nothing else can start reading or freeing
+        // `data` underneath us.
+        if arc
+            .data()
+            .arc_count
+            .compare_exchange(1, 0, Relaxed, Relaxed)
+            .is_err()
+        {
+            // Other `Arc`s are pointing at the same allocation; clone
+            // the data into a fresh one that only we own.
+            *arc = Arc::new((**arc).clone());
+        } else {
+            // Acquire matches the `Release` decrement in `Drop for Arc`,
+            // so that the data is visible to us.
+            fence(Acquire);
+            if arc.data().alloc_count.load(Relaxed) != 1 {
+                // We're the only `Arc`, but `Weak`s exist; move the data
+                // out into a new allocation so they can no longer
+                // observe it, then drop the implicit weak that the
+                // (already-zeroed) strong count held on the old
+                // allocation, which frees it without running `T`'s
+                // destructor.
+                unsafe {
+                    let data = ManuallyDrop::take(&mut *arc.data().data.get());
+                    drop(Weak { ptr: arc.ptr });
+                    std::ptr::write(arc, Arc::new(data));
+                }
+            } else {
+                // We were unique all along; restore the strong count we
+                // claimed above.
+                arc.data().arc_count.store(1, Release);
+            }
+        }
+        // At this point we're guaranteed unique.
+        unsafe { &mut *arc.data().data.get() }
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
     pub fn downgrade(arc: &Self) -> Weak<T> {
         let mut n = arc.data().alloc_count.load(Relaxed);
         loop {
+            if n == usize::MAX {
+                // `get_mut` holds the lock; spin until it
+                // releases `alloc_count` back to a real count, otherwise
+                // we'd mint a `Weak` that `get_mut` never accounted for.
+                std::hint::spin_loop();
+                n = arc.data().alloc_count.load(Relaxed);
+                continue;
+            }
             if let Err(e) = arc
                 .data()
                 .alloc_count
@@ -106,12 +281,69 @@ impl<T> Arc<T> {
     fn data(&self) -> &Data<T> {
         unsafe { self.ptr.as_ref() }
     }
+
+    /// Returns a mutable reference into the given `Arc`, if there are
+    /// no other `Arc` or `Weak` pointers to the same allocation.
+    ///
+    /// Returns `None` otherwise, because it would otherwise be unsound to
+    /// mutate a shared value.
+    pub fn get_mut(arc: &mut Self) -> Option<&mut T> {
+        // Lock the allocation so that no `Weak` can be upgraded or
+        // downgraded from while we check whether we are the only `Arc`.
+        // `usize::MAX` is the one count no real allocation can reach (it
+        // would already have aborted in `Clone for Weak`), and
+        // `downgrade` spins rather than CAS-ing against it, so
+        // unlike any other sentinel it can never be clobbered by a
+        // concurrent `Weak` being minted.
+        if arc
+            .data()
+            .alloc_count
+            .compare_exchange(1, usize::MAX, Acquire, Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        let is_unique = arc.data().arc_count.load(Acquire) == 1;
+        arc.data().alloc_count.store(1, Release);
+        if !is_unique {
+            return None;
+        }
+        // Acquire matches the `Release` decrement in `Drop for Arc`, so
+        // that the data is visible to us.
+        fence(Acquire);
+        unsafe { Some(&mut *arc.data().data.get()) }
+    }
+
+    /// Returns the number of `Arc`s pointing at this allocation.
+    pub fn strong_count(this: &Self) -> usize {
+        this.data().arc_count.load(Acquire)
+    }
+
+    /// Returns the number of `Weak`s pointing at this allocation.
+    pub fn weak_count(this: &Self) -> usize {
+        // `alloc_count` also counts the implicit weak representing the
+        // whole group of `Arc`s, so subtract that one back out.
+        let n = this.data().alloc_count.load(Acquire);
+        // `usize::MAX` is the lock `get_mut` takes, which it only does
+        // while `alloc_count` is 1, i.e. while there are no `Weak`s.
+        if n == usize::MAX {
+            return 0;
+        }
+        n.saturating_sub(1)
+    }
+
+    /// Returns `true` if the two `Arc`s point at the same allocation.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        std::ptr::eq(this.ptr.as_ptr(), other.ptr.as_ptr())
+    }
 }
 
-unsafe impl<T: Sync + Send> Send for Arc<T> {}
-unsafe impl<T: Sync + Send> Sync for Arc<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Send for Arc<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Sync for Arc<T> {}
+
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<Arc<U>> for Arc<T> {}
 
-impl<T> Deref for Arc<T> {
+impl<T: ?Sized> Deref for Arc<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -119,7 +351,7 @@ impl<T> Deref for Arc<T> {
     }
 }
 
-impl<T> Clone for Arc<T> {
+impl<T: ?Sized> Clone for Arc<T> {
     fn clone(&self) -> Self {
         if self.data().arc_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
             abort();
@@ -128,7 +360,7 @@ impl<T> Clone for Arc<T> {
     }
 }
 
-impl<T> Drop for Arc<T> {
+impl<T: ?Sized> Drop for Arc<T> {
     fn drop(&mut self) {
         if self.data().arc_count.fetch_sub(1, Release) == 1 {
             fence(Acquire);
@@ -140,6 +372,87 @@ impl<T> Drop for Arc<T> {
     }
 }
 
+impl<T> From<T> for Arc<T> {
+    fn from(data: T) -> Self {
+        Arc::new(data)
+    }
+}
+
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Arc::new(T::default())
+    }
+}
+
+impl<T: ?Sized> AsRef<T> for Arc<T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized> Borrow<T> for Arc<T> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized + PartialEq> PartialEq for Arc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        // No `ptr_eq` short-circuit here: for non-reflexive `T` (e.g.
+        // `f64::NAN`), two `Arc`s sharing an allocation must still
+        // compare unequal to themselves, so we always defer to `T::eq`.
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for Arc<T> {}
+
+impl<T: ?Sized + PartialOrd> PartialOrd for Arc<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord> Ord for Arc<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Hash> Hash for Arc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl Arc<dyn Any + Send + Sync> {
+    /// Attempts to downcast the type-erased `Arc` to a concrete type.
+    ///
+    /// On success, reuses the existing allocation: no counts change.
+    pub fn downcast<U: Any>(self) -> Result<Arc<U>, Self> {
+        if (*self).is::<U>() {
+            let ptr = self.ptr.cast::<Data<U>>();
+            // The new `Arc<U>` takes over the counts that `self` held.
+            std::mem::forget(self);
+            Ok(Arc { ptr })
+        } else {
+            Err(self)
+        }
+    }
+}
+
 #[test]
 fn test() {
     static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
@@ -176,3 +489,90 @@ fn test() {
 
     assert_eq!(NUM_DROPS.load(Relaxed), 1);
 }
+
+#[test]
+fn new_cyclic_back_reference() {
+    struct Node {
+        me: Weak<Node>,
+    }
+
+    let arc = Arc::new_cyclic(|me| Node { me: me.clone() });
+    let upgraded = arc.me.upgrade().unwrap();
+    assert!(upgraded.me.upgrade().is_some());
+}
+
+#[test]
+fn new_cyclic_upgrade_during_construction_returns_none() {
+    struct Node {
+        upgraded_early: bool,
+    }
+
+    let arc = Arc::new_cyclic(|me| Node {
+        upgraded_early: me.upgrade().is_some(),
+    });
+    assert!(!arc.upgraded_early);
+}
+
+#[test]
+fn trait_forwarding() {
+    use std::collections::HashMap;
+
+    let a: Arc<i32> = 5.into();
+    let b = Arc::new(5);
+    assert_eq!(a, b);
+    assert!(a <= b);
+    assert_eq!(format!("{}", a), "5");
+    assert_eq!(format!("{:?}", a), "5");
+
+    let default: Arc<i32> = Arc::default();
+    assert_eq!(*default, 0);
+
+    let mut map = HashMap::new();
+    map.insert(Arc::new("key"), 1);
+    assert_eq!(map.get(&Arc::new("key")), Some(&1));
+}
+
+#[test]
+fn partial_eq_does_not_shortcut_on_identity() {
+    let a = Arc::new(f64::NAN);
+    let b = a.clone();
+    assert!(Arc::ptr_eq(&a, &b));
+    assert_ne!(a, b);
+}
+
+#[test]
+fn counts_and_identity() {
+    let arc = Arc::new(5);
+    let arc2 = arc.clone();
+    let weak = Arc::downgrade(&arc);
+
+    assert_eq!(Arc::strong_count(&arc), 2);
+    assert_eq!(Arc::weak_count(&arc), 1);
+    assert!(Arc::ptr_eq(&arc, &arc2));
+    assert!(!Arc::ptr_eq(&arc, &Arc::new(5)));
+
+    drop(weak);
+    assert_eq!(Arc::weak_count(&arc), 0);
+
+    let dangling: Weak<i32> = Weak::new();
+    assert!(dangling.upgrade().is_none());
+}
+
+#[test]
+fn any_downcast() {
+    let arc: Arc<dyn Any + Send + Sync> = Arc::new(42i32);
+    let arc = arc.downcast::<i32>().unwrap();
+    assert_eq!(*arc, 42);
+
+    let arc: Arc<dyn Any + Send + Sync> = Arc::new(42i32);
+    assert!(arc.downcast::<String>().is_err());
+}
+
+#[test]
+fn unsized_coercion() {
+    let arc: Arc<[i32]> = Arc::new([1, 2, 3]);
+    assert_eq!(&*arc, &[1, 2, 3]);
+
+    let arc: Arc<dyn Fn() -> i32> = Arc::new(|| 42);
+    assert_eq!(arc(), 42);
+}